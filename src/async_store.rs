@@ -0,0 +1,155 @@
+//! Async mirror of `KeyValueStore`, feature-gated behind `async-tokio`.
+//!
+//! Rather than reimplement file I/O on top of `tokio::fs`, this wraps the
+//! existing `DiskStore` (with all its codec/cipher/preamble/merge logic
+//! already in place) behind a `tokio::sync::RwLock` and offloads the
+//! blocking calls to tokio's blocking thread pool via `spawn_blocking`.
+//! Reads take the shared read side of the lock, so any number of
+//! `get`/`get_many` calls can run concurrently; `set`/`delete` take the
+//! exclusive write side, matching `DiskStore`'s single-writer invariant.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use bincode::Encode;
+use tokio::{sync::RwLock, task};
+
+use crate::{DatabaseError, KeyValueStore, disk_store::DiskStore};
+
+// Allow: this trait's only implementor is `AsyncDiskStore` and nothing
+// dispatches it dynamically, so the auto-trait bounds `async fn in trait`
+// normally hides aren't a problem here. Desugaring to `-> impl Future + Send`
+// would be a bigger, API-visible change than this fix warrants.
+#[allow(async_fn_in_trait)]
+pub trait AsyncKeyValueStore {
+    async fn get<K: Encode + Send + 'static>(
+        &self,
+        key: K,
+    ) -> Result<Option<Vec<u8>>, DatabaseError>;
+    async fn set<K: Encode + Send + 'static, V: Encode + Send + 'static>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(), DatabaseError>;
+    async fn delete<K: Encode + Send + 'static>(&self, key: K) -> Result<(), DatabaseError>;
+}
+
+#[derive(Clone)]
+pub struct AsyncDiskStore {
+    inner: Arc<RwLock<DiskStore>>,
+}
+
+impl AsyncDiskStore {
+    pub async fn new(dir: PathBuf) -> Result<Self, DatabaseError> {
+        let store = task::spawn_blocking(move || DiskStore::new(dir))
+            .await
+            .expect("DiskStore::new panicked")?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(store)),
+        })
+    }
+
+    /// Async mirror of `DiskStore::get_many`, run under the lock's shared
+    /// read side so a batch lookup doesn't starve concurrent `get`s.
+    pub async fn get_many<K: Encode + Send + 'static>(
+        &self,
+        keys: Vec<K>,
+    ) -> Result<HashMap<Vec<u8>, Vec<u8>>, DatabaseError> {
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || inner.blocking_read().get_many(&keys))
+            .await
+            .expect("DiskStore::get_many panicked")
+    }
+}
+
+impl AsyncKeyValueStore for AsyncDiskStore {
+    async fn get<K: Encode + Send + 'static>(
+        &self,
+        key: K,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || inner.blocking_read().get(key))
+            .await
+            .expect("DiskStore::get panicked")
+    }
+
+    async fn set<K: Encode + Send + 'static, V: Encode + Send + 'static>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(), DatabaseError> {
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || inner.blocking_write().set(key, value))
+            .await
+            .expect("DiskStore::set panicked")
+    }
+
+    async fn delete<K: Encode + Send + 'static>(&self, key: K) -> Result<(), DatabaseError> {
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || inner.blocking_write().delete(key))
+            .await
+            .expect("DiskStore::delete panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::format::CONFIG;
+
+    #[tokio::test]
+    async fn test_get_set_delete_round_trip() {
+        let dir = tempdir().unwrap();
+        let store = AsyncDiskStore::new(dir.path().to_path_buf()).await.unwrap();
+
+        assert_eq!(store.get("name").await.unwrap(), None);
+
+        store.set("name", "jojo").await.unwrap();
+        assert_eq!(
+            store.get("name").await.unwrap().unwrap(),
+            bincode::encode_to_vec("jojo", CONFIG).unwrap()
+        );
+
+        store.delete("name").await.unwrap();
+        assert_eq!(store.get("name").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_many() {
+        let dir = tempdir().unwrap();
+        let store = AsyncDiskStore::new(dir.path().to_path_buf()).await.unwrap();
+
+        store.set("hamlet", "shakespeare").await.unwrap();
+        store.set("othello", "shakespeare").await.unwrap();
+
+        let found = store
+            .get_many(vec!["hamlet", "othello", "no such key"])
+            .await
+            .unwrap();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            found[&bincode::encode_to_vec("hamlet", CONFIG).unwrap()],
+            bincode::encode_to_vec("shakespeare", CONFIG).unwrap()
+        );
+        assert_eq!(
+            found[&bincode::encode_to_vec("othello", CONFIG).unwrap()],
+            bincode::encode_to_vec("shakespeare", CONFIG).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reads_both_succeed() {
+        let dir = tempdir().unwrap();
+        let store = AsyncDiskStore::new(dir.path().to_path_buf()).await.unwrap();
+        store.set("name", "jojo").await.unwrap();
+
+        // Both reads take the lock's shared read side. This checks that two
+        // reads issued together both complete with the stored value; it
+        // does not measure whether they actually overlapped.
+        let (a, b) = tokio::join!(store.get("name"), store.get("name"));
+        assert_eq!(a.unwrap().unwrap(), bincode::encode_to_vec("jojo", CONFIG).unwrap());
+        assert_eq!(b.unwrap().unwrap(), bincode::encode_to_vec("jojo", CONFIG).unwrap());
+    }
+}
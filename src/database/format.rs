@@ -1,13 +1,15 @@
-use bincode::{
-    Encode,
-    config::{self, Configuration},
-    error::EncodeError,
-};
+use bincode::config::{self, Configuration};
+#[cfg(test)]
+use bincode::{Encode, error::EncodeError};
+use crc32fast::Hasher;
 use thiserror::Error;
 
 pub const CONFIG: Configuration = config::standard();
 
-pub const HEADER_SIZE: usize = size_of::<Header>();
+// Header no longer packs neatly into four u32s once the compression and
+// encryption tags are added, so size this explicitly instead of trusting
+// `size_of` to match the hand-rolled encode/decode layout.
+pub const HEADER_SIZE: usize = 4 + 4 + 4 + 4 + 1 + 1;
 
 #[derive(Error, Debug)]
 pub enum FormatError {
@@ -17,57 +19,251 @@ pub enum FormatError {
     Key(usize, usize),
     #[error("value of incorrect size `{0}`. Size should be {1}")]
     Value(usize, usize),
+    #[error("hint entry header of incorrect size `{0}`. Size should be {1}")]
+    Hint(usize, usize),
+    #[error("crc mismatch: expected `{expected}`, got `{actual}`")]
+    Crc { expected: u32, actual: u32 },
+    #[error("unknown compression id `{0}`")]
+    UnknownCompression(u8),
+    #[error("record was written with {0:?} compression, which this build was not compiled with")]
+    CompressionUnavailable(Compression),
+    #[error("unknown encryption id `{0}`")]
+    UnknownEncryption(u8),
+    #[error("record was written with {0:?} encryption, which this build was not compiled with")]
+    EncryptionUnavailable(EncryptionType),
+    #[error("not a caskdb datafile: bad magic signature")]
+    BadMagic,
+    #[error("unsupported format version `{0}`")]
+    UnsupportedVersion(u8),
+}
+
+/// 8-byte magic signature written at the start of every datafile, modeled
+/// on PNG's signature scheme: a non-ASCII first byte (so a transfer that
+/// strips the high bit is caught immediately), an ASCII tag identifying
+/// the format, and a CR-LF-EOF sequence that a bad line-ending conversion
+/// would mangle.
+pub(crate) const MAGIC: [u8; 8] = [0x89, b'C', b'A', b'S', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Current on-disk format version, written as the single byte right after
+/// `MAGIC`. Bump this whenever the record layout changes in a way old
+/// code can't read.
+pub(crate) const FORMAT_VERSION: u8 = 1;
+
+/// Size of the magic + version preamble written at the start of every
+/// datafile, before its first record.
+pub(crate) const PREAMBLE_SIZE: usize = MAGIC.len() + 1;
+
+/// Builds the preamble bytes a freshly created datafile should start with.
+pub(crate) fn encode_preamble() -> [u8; PREAMBLE_SIZE] {
+    let mut buf = [0u8; PREAMBLE_SIZE];
+    buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+    buf[MAGIC.len()] = FORMAT_VERSION;
+    buf
+}
+
+/// Validates a datafile's leading bytes against `MAGIC` and `FORMAT_VERSION`
+/// before anything else is read from it.
+pub(crate) fn validate_preamble(buf: &[u8; PREAMBLE_SIZE]) -> Result<(), FormatError> {
+    if buf[..MAGIC.len()] != MAGIC {
+        return Err(FormatError::BadMagic);
+    }
+    let version = buf[MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(FormatError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+/// Value compression codec, recorded per-record so rotated or merged
+/// datafiles can each be read back regardless of what codec wrote them.
+/// Every non-`None` variant is gated behind its own cargo feature; a codec
+/// id that isn't compiled in surfaces as `FormatError::CompressionUnavailable`
+/// instead of silently returning the still-compressed bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+    Lzma = 2,
+    Bzip2 = 3,
+}
+
+impl Compression {
+    pub(crate) fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Result<Self, FormatError> {
+        match byte {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Lzma),
+            3 => Ok(Self::Bzip2),
+            other => Err(FormatError::UnknownCompression(other)),
+        }
+    }
+}
+
+/// At-rest encryption cipher, recorded per-record just like `Compression` so
+/// that rotated or merged datafiles stay readable regardless of which
+/// cipher wrote them. The key bytes are never encrypted — only the value —
+/// since `key_dir` and hint-file replay both need the plaintext key to
+/// index records; see `DiskStore`'s `get`/`set` for where the cipher is
+/// actually applied. Every non-`None` variant is gated behind its own
+/// cargo feature, mirroring `Compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionType {
+    None = 0,
+    AesGcm = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl EncryptionType {
+    pub(crate) fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Result<Self, FormatError> {
+        match byte {
+            0 => Ok(Self::None),
+            1 => Ok(Self::AesGcm),
+            2 => Ok(Self::ChaCha20Poly1305),
+            other => Err(FormatError::UnknownEncryption(other)),
+        }
+    }
+}
+
+/// Computes the CRC32 over the header tail (everything but the crc field
+/// itself) followed by the key and value bytes. Encode and decode must feed
+/// this the exact same byte range or the checksum is meaningless.
+///
+/// When encryption is enabled the AEAD tag already authenticates the value
+/// bytes, making this crc largely redundant for those records; it's still
+/// computed unconditionally rather than branching on `encryption`, since a
+/// record can be relocated by `merge` without whoever reads it knowing in
+/// advance whether the tag check already ran.
+fn compute_crc(
+    timestamp: u32,
+    key_size: u32,
+    value_size: u32,
+    compression: u8,
+    encryption: u8,
+    key: &[u8],
+    value: &[u8],
+) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(&timestamp.to_be_bytes());
+    hasher.update(&key_size.to_be_bytes());
+    hasher.update(&value_size.to_be_bytes());
+    hasher.update(&[compression]);
+    hasher.update(&[encryption]);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
 }
 
 #[derive(Clone, Debug, PartialEq)]
-struct Record {
-    header: Header,
+pub(crate) struct Record {
+    pub(crate) header: Header,
     key: Vec<u8>,
-    value: Vec<u8>,
+    pub(crate) value: Vec<u8>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
-struct Header {
+pub(crate) struct Header {
     crc: u32,
     timestamp: u32,
     key_size: u32,
     value_size: u32,
+    compression: u8,
+    encryption: u8,
 }
 
 impl Header {
-    fn encode(self) -> Vec<u8> {
+    pub(crate) fn encode(self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(HEADER_SIZE);
         ret.extend(self.crc.to_be_bytes());
         ret.extend(self.timestamp.to_be_bytes());
         ret.extend(self.key_size.to_be_bytes());
         ret.extend(self.value_size.to_be_bytes());
+        ret.push(self.compression);
+        ret.push(self.encryption);
         ret
     }
 
-    fn decode(bytes: [u8; HEADER_SIZE]) -> Self {
+    pub(crate) fn decode(bytes: [u8; HEADER_SIZE]) -> Self {
         Self {
             crc: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
             timestamp: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
             key_size: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
             value_size: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+            compression: bytes[16],
+            encryption: bytes[17],
         }
     }
+
+    pub(crate) fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    pub(crate) fn key_size(&self) -> u32 {
+        self.key_size
+    }
+
+    pub(crate) fn value_size(&self) -> u32 {
+        self.value_size
+    }
+
+    /// Raw compression tag as stored on disk; see `Compression::from_byte`
+    /// to validate and interpret it.
+    pub(crate) fn compression_byte(&self) -> u8 {
+        self.compression
+    }
+
+    /// Raw encryption tag as stored on disk; see `EncryptionType::from_byte`
+    /// to validate and interpret it.
+    pub(crate) fn encryption_byte(&self) -> u8 {
+        self.encryption
+    }
+
+    /// Number of bytes actually stored on disk after the header: the key,
+    /// plus the value, or nothing at all for a tombstone.
+    pub(crate) fn total_size(&self) -> u32 {
+        self.key_size + if self.is_tombstone() { 0 } else { self.value_size }
+    }
+
+    /// A tombstone is marked by the sentinel `value_size == u32::MAX`,
+    /// which is otherwise not a size any real value can have.
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.value_size == u32::MAX
+    }
 }
 
 impl Record {
-    pub fn new(timestamp: u32, key: Vec<u8>, value: Vec<u8>) -> Self {
+    pub fn new(
+        timestamp: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        compression: Compression,
+        encryption: EncryptionType,
+    ) -> Self {
         Self {
             header: Header {
                 crc: 0,
                 timestamp,
                 key_size: key.len() as u32,
                 value_size: value.len() as u32,
+                compression: compression.to_byte(),
+                encryption: encryption.to_byte(),
             },
             key,
             value,
         }
     }
 
+    /// Only used by this module's own tests; call sites elsewhere in the
+    /// crate go through `DiskStore::set`, which needs to apply compression
+    /// and encryption in between encoding and building the `Record`.
+    #[cfg(test)]
     pub fn try_new<K: Encode, V: Encode>(
         timestamp: u32,
         key: K,
@@ -75,11 +271,66 @@ impl Record {
     ) -> Result<Self, EncodeError> {
         let key = bincode::encode_to_vec(key, CONFIG)?;
         let value = bincode::encode_to_vec(value, CONFIG)?;
-        Ok(Self::new(timestamp, key, value))
+        Ok(Self::new(
+            timestamp,
+            key,
+            value,
+            Compression::None,
+            EncryptionType::None,
+        ))
+    }
+
+    /// A tombstone marking `key` as deleted. It carries no value bytes on
+    /// disk; `Header::is_tombstone` is what distinguishes it from a record
+    /// that legitimately stores an empty value.
+    pub fn new_tombstone(timestamp: u32, key: Vec<u8>) -> Self {
+        Self {
+            header: Header {
+                crc: 0,
+                timestamp,
+                key_size: key.len() as u32,
+                value_size: u32::MAX,
+                compression: Compression::None.to_byte(),
+                encryption: EncryptionType::None.to_byte(),
+            },
+            key,
+            value: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.header.is_tombstone()
+    }
+
+    pub(crate) fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The codec `self.value` is stored under. Callers are responsible for
+    /// decompressing it; `Record::decode` never touches the value bytes.
+    pub(crate) fn compression(&self) -> Result<Compression, FormatError> {
+        Compression::from_byte(self.header.compression_byte())
     }
 
-    pub fn encode(self) -> Vec<u8> {
+    /// The cipher `self.value` is encrypted under, if any. Callers are
+    /// responsible for decrypting it; `Record::decode` never touches the
+    /// value bytes.
+    pub(crate) fn encryption(&self) -> Result<EncryptionType, FormatError> {
+        EncryptionType::from_byte(self.header.encryption_byte())
+    }
+
+    pub fn encode(mut self) -> Vec<u8> {
         let size = HEADER_SIZE + self.key.len() + self.value.len();
+        self.header.crc = compute_crc(
+            self.header.timestamp,
+            self.header.key_size,
+            self.header.value_size,
+            self.header.compression,
+            self.header.encryption,
+            &self.key,
+            &self.value,
+        );
+
         let mut ret = Vec::with_capacity(size);
         ret.extend(self.header.encode());
         ret.extend(self.key);
@@ -94,19 +345,38 @@ impl Record {
         let header_data = data.drain(0..HEADER_SIZE).collect::<Vec<_>>();
         let header = Header::decode(header_data.try_into().unwrap());
 
-        let key: Vec<u8> = data.drain(0..header.key_size as usize).collect();
-        if key.len() != header.key_size as usize {
-            return Err(FormatError::Key(
-                key.len(),
-                header.key_size as usize,
-            ));
+        let key_size = header.key_size as usize;
+        if data.len() < key_size {
+            return Err(FormatError::Key(data.len(), key_size));
         }
-        let value: Vec<u8> = data.drain(0..header.value_size as usize).collect();
-        if value.len() != header.value_size as usize {
-            return Err(FormatError::Value(
-                value.len(),
-                header.value_size as usize,
-            ));
+        let key: Vec<u8> = data.drain(0..key_size).collect();
+
+        // A tombstone's header.value_size is the sentinel u32::MAX, not a
+        // real length: no value bytes follow it on disk.
+        let value_size = if header.is_tombstone() {
+            0
+        } else {
+            header.value_size as usize
+        };
+        if data.len() < value_size {
+            return Err(FormatError::Value(data.len(), value_size));
+        }
+        let value: Vec<u8> = data.drain(0..value_size).collect();
+
+        let actual = compute_crc(
+            header.timestamp,
+            header.key_size,
+            header.value_size,
+            header.compression,
+            header.encryption,
+            &key,
+            &value,
+        );
+        if actual != header.crc {
+            return Err(FormatError::Crc {
+                expected: header.crc,
+                actual,
+            });
         }
 
         Ok(Self { header, key, value })
@@ -117,8 +387,6 @@ impl Record {
 mod tests {
     use super::*;
 
-    use getrandom;
-
     fn get_random_u32() -> u32 {
         let mut buf = [0u8; 4];
         getrandom::fill(&mut buf).unwrap();
@@ -136,6 +404,8 @@ mod tests {
             timestamp: get_random_u32(),
             key_size: get_random_u32(),
             value_size: get_random_u32(),
+            compression: get_random_u32() as u8,
+            encryption: get_random_u32() as u8,
         }
     }
 
@@ -156,7 +426,112 @@ mod tests {
     fn kv_test(kv: Record) {
         let data = kv.clone().encode();
         let same_kv = Record::decode(data).unwrap();
-        assert_eq!(kv, same_kv)
+        // `encode` fills in the real crc, so compare everything else and
+        // trust that a successful `decode` already means the crc matched.
+        assert_eq!(kv.key, same_kv.key);
+        assert_eq!(kv.value, same_kv.value);
+        assert_eq!(kv.header.timestamp, same_kv.header.timestamp);
+        assert_eq!(kv.header.key_size, same_kv.header.key_size);
+        assert_eq!(kv.header.value_size, same_kv.header.value_size);
+        assert_eq!(kv.header.compression, same_kv.header.compression);
+        assert_eq!(kv.header.encryption, same_kv.header.encryption);
+    }
+
+    #[test]
+    fn test_crc_mismatch_detected() {
+        let kv = Record::try_new(now_timestamp(), "hello", "world").unwrap();
+        let mut data = kv.encode();
+        // Flip a byte in the value to simulate on-disk corruption.
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(matches!(Record::decode(data), Err(FormatError::Crc { .. })));
+    }
+
+    #[test]
+    fn test_tombstone_round_trip() {
+        let tombstone = Record::new_tombstone(now_timestamp(), b"hello".to_vec());
+        assert!(tombstone.is_tombstone());
+
+        let data = tombstone.clone().encode();
+        assert_eq!(data.len(), HEADER_SIZE + tombstone.key.len());
+
+        let decoded = Record::decode(data).unwrap();
+        assert!(decoded.is_tombstone());
+        assert_eq!(decoded.key, tombstone.key);
+        assert!(decoded.value.is_empty());
+    }
+
+    #[test]
+    fn test_compression_tag_round_trip() {
+        let record = Record::new(
+            now_timestamp(),
+            b"k".to_vec(),
+            b"v".to_vec(),
+            Compression::Zstd,
+            EncryptionType::None,
+        );
+        assert_eq!(record.compression().unwrap(), Compression::Zstd);
+
+        let decoded = Record::decode(record.encode()).unwrap();
+        assert_eq!(decoded.compression().unwrap(), Compression::Zstd);
+    }
+
+    #[test]
+    fn test_unknown_compression_id_rejected() {
+        assert!(matches!(
+            Compression::from_byte(42),
+            Err(FormatError::UnknownCompression(42))
+        ));
+    }
+
+    #[test]
+    fn test_encryption_tag_round_trip() {
+        let record = Record::new(
+            now_timestamp(),
+            b"k".to_vec(),
+            b"v".to_vec(),
+            Compression::None,
+            EncryptionType::AesGcm,
+        );
+        assert_eq!(record.encryption().unwrap(), EncryptionType::AesGcm);
+
+        let decoded = Record::decode(record.encode()).unwrap();
+        assert_eq!(decoded.encryption().unwrap(), EncryptionType::AesGcm);
+    }
+
+    #[test]
+    fn test_unknown_encryption_id_rejected() {
+        assert!(matches!(
+            EncryptionType::from_byte(42),
+            Err(FormatError::UnknownEncryption(42))
+        ));
+    }
+
+    #[test]
+    fn test_preamble_round_trip() {
+        let preamble = encode_preamble();
+        assert!(validate_preamble(&preamble).is_ok());
+    }
+
+    #[test]
+    fn test_bad_magic_rejected() {
+        let mut preamble = encode_preamble();
+        preamble[0] ^= 0xff;
+        assert!(matches!(
+            validate_preamble(&preamble),
+            Err(FormatError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_version_rejected() {
+        let mut preamble = encode_preamble();
+        let bad_version = FORMAT_VERSION.wrapping_add(1);
+        preamble[MAGIC.len()] = bad_version;
+        assert!(matches!(
+            validate_preamble(&preamble),
+            Err(FormatError::UnsupportedVersion(v)) if v == bad_version
+        ));
     }
 
     #[test]
@@ -167,18 +542,24 @@ mod tests {
                 timestamp: 10,
                 key_size: 10,
                 value_size: 10,
+                compression: 0,
+                encryption: 0,
             },
             Header {
                 crc: 0,
                 timestamp: 0,
                 key_size: 0,
                 value_size: 0,
+                compression: 0,
+                encryption: 0,
             },
             Header {
                 crc: 0,
                 timestamp: 10000,
                 key_size: 10000,
                 value_size: 10000,
+                compression: 0,
+                encryption: 0,
             },
         ];
         for header in tests {
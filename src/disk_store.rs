@@ -1,58 +1,412 @@
 use bincode::Encode;
 use dashmap::DashMap;
 use std::{
-    fs::File,
-    io::{Read, Seek, Write},
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
 };
 
 use super::{
     DatabaseError, KeyDirectory, KeyEntry, KeyValueStore,
-    format::{CONFIG, FormatError, HEADER_SIZE, Header, Record},
+    format::{
+        self, CONFIG, Compression, EncryptionType, FormatError, HEADER_SIZE, Header,
+        PREAMBLE_SIZE, Record,
+    },
 };
 
+/// Rotate the active file once it grows past this size so a single
+/// datafile never grows unbounded and so `merge` has something to do.
+const DEFAULT_MAX_ACTIVE_FILE_SIZE: u64 = 1024 * 1024;
+
+const DATA_EXTENSION: &str = "data";
+const HINT_EXTENSION: &str = "hint";
+
+/// Size of one hint file entry's fixed header: timestamp, key_size,
+/// value_size, value_offset.
+const HINT_HEADER_SIZE: usize = 4 + 4 + 4 + 8;
+
+/// Nonce size used by both supported AEAD ciphers.
+#[cfg(any(feature = "encrypt-aes-gcm", feature = "encrypt-chacha20poly1305"))]
+const NONCE_SIZE: usize = 12;
+
+/// Size of the random salt used to derive the data key from a passphrase.
+/// Stored once per database alongside the datafiles.
+const SALT_SIZE: usize = 16;
+
+const SALT_FILE_NAME: &str = "salt";
+
+impl Compression {
+    /// Compresses `data` with the configured codec. `None` is a no-op copy
+    /// so callers don't need to special-case it.
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "compress-zstd")]
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+            #[cfg(feature = "compress-lzma")]
+            Compression::Lzma => {
+                let mut out = Vec::new();
+                xz2::read::XzEncoder::new(data, 6).read_to_end(&mut out)?;
+                Ok(out)
+            }
+            #[cfg(feature = "compress-bzip2")]
+            Compression::Bzip2 => {
+                let mut out = Vec::new();
+                bzip2::read::BzEncoder::new(data, bzip2::Compression::default())
+                    .read_to_end(&mut out)?;
+                Ok(out)
+            }
+            #[allow(unreachable_patterns)]
+            other => Err(DatabaseError::Format(FormatError::CompressionUnavailable(
+                other,
+            ))),
+        }
+    }
+
+    /// Decompresses `data` that was written with this codec.
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "compress-zstd")]
+            Compression::Zstd => Ok(zstd::decode_all(data)?),
+            #[cfg(feature = "compress-lzma")]
+            Compression::Lzma => {
+                let mut out = Vec::new();
+                xz2::read::XzDecoder::new(data).read_to_end(&mut out)?;
+                Ok(out)
+            }
+            #[cfg(feature = "compress-bzip2")]
+            Compression::Bzip2 => {
+                let mut out = Vec::new();
+                bzip2::read::BzDecoder::new(data).read_to_end(&mut out)?;
+                Ok(out)
+            }
+            #[allow(unreachable_patterns)]
+            other => Err(DatabaseError::Format(FormatError::CompressionUnavailable(
+                other,
+            ))),
+        }
+    }
+}
+
+#[cfg(any(feature = "encrypt-aes-gcm", feature = "encrypt-chacha20poly1305"))]
+fn random_nonce() -> Result<[u8; NONCE_SIZE], DatabaseError> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::fill(&mut nonce).map_err(|e| DatabaseError::Io(io::Error::other(e.to_string())))?;
+    Ok(nonce)
+}
+
+impl EncryptionType {
+    /// Encrypts `data` under `data_key` with a fresh random nonce, returning
+    /// the nonce followed by the ciphertext (with its authentication tag
+    /// appended) so `decrypt` can recover both from a single blob.
+    #[allow(unused_variables)]
+    fn encrypt(self, data_key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            EncryptionType::None => Ok(data.to_vec()),
+            #[cfg(feature = "encrypt-aes-gcm")]
+            EncryptionType::AesGcm => {
+                use aes_gcm::{
+                    Aes256Gcm, Nonce,
+                    aead::{Aead, KeyInit},
+                };
+                let nonce = random_nonce()?;
+                let cipher = Aes256Gcm::new(data_key.into());
+                let ciphertext = cipher
+                    .encrypt(Nonce::from_slice(&nonce), data)
+                    .map_err(|_| DatabaseError::Decryption)?;
+                let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+                out.extend(nonce);
+                out.extend(ciphertext);
+                Ok(out)
+            }
+            #[cfg(feature = "encrypt-chacha20poly1305")]
+            EncryptionType::ChaCha20Poly1305 => {
+                use chacha20poly1305::{
+                    ChaCha20Poly1305, Nonce,
+                    aead::{Aead, KeyInit},
+                };
+                let nonce = random_nonce()?;
+                let cipher = ChaCha20Poly1305::new(data_key.into());
+                let ciphertext = cipher
+                    .encrypt(Nonce::from_slice(&nonce), data)
+                    .map_err(|_| DatabaseError::Decryption)?;
+                let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+                out.extend(nonce);
+                out.extend(ciphertext);
+                Ok(out)
+            }
+            #[allow(unreachable_patterns)]
+            other => Err(DatabaseError::Format(FormatError::EncryptionUnavailable(
+                other,
+            ))),
+        }
+    }
+
+    /// Splits the nonce back off `data` and decrypts the remainder under
+    /// `data_key`, verifying the AEAD tag. A failed tag check (wrong key,
+    /// wrong nonce, or tampered ciphertext) surfaces as
+    /// `DatabaseError::Decryption`, distinct from `FormatError::Crc`. Stored
+    /// bytes that were altered without recomputing the record's crc are
+    /// already rejected with `FormatError::Crc` by `Record::decode`, before
+    /// this is called.
+    #[allow(unused_variables)]
+    fn decrypt(self, data_key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            EncryptionType::None => Ok(data.to_vec()),
+            #[cfg(feature = "encrypt-aes-gcm")]
+            EncryptionType::AesGcm => {
+                use aes_gcm::{
+                    Aes256Gcm, Nonce,
+                    aead::{Aead, KeyInit},
+                };
+                if data.len() < NONCE_SIZE {
+                    return Err(DatabaseError::Decryption);
+                }
+                let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+                let cipher = Aes256Gcm::new(data_key.into());
+                cipher
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| DatabaseError::Decryption)
+            }
+            #[cfg(feature = "encrypt-chacha20poly1305")]
+            EncryptionType::ChaCha20Poly1305 => {
+                use chacha20poly1305::{
+                    ChaCha20Poly1305, Nonce,
+                    aead::{Aead, KeyInit},
+                };
+                if data.len() < NONCE_SIZE {
+                    return Err(DatabaseError::Decryption);
+                }
+                let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+                let cipher = ChaCha20Poly1305::new(data_key.into());
+                cipher
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| DatabaseError::Decryption)
+            }
+            #[allow(unreachable_patterns)]
+            other => Err(DatabaseError::Format(FormatError::EncryptionUnavailable(
+                other,
+            ))),
+        }
+    }
+}
+
+/// The synchronous, on-disk `KeyValueStore` implementation. `pub` so it can
+/// be used directly by callers who don't need `AsyncDiskStore`'s tokio
+/// wrapper; the async wrapper in `async_store` holds one behind a lock.
 #[derive(Debug)]
-struct DiskStore {
-    // dir: PathBuf,
+pub struct DiskStore {
+    dir: PathBuf,
     file: File,
     active_path: PathBuf,
     key_dir: KeyDirectory,
     write_position: u64,
+    max_active_file_size: u64,
+    compression: Compression,
+    encryption: EncryptionType,
+    data_key: Option<[u8; 32]>,
 }
 
 impl DiskStore {
-    pub fn new(file: PathBuf) -> Result<Self, DatabaseError> {
-        // let parent = file.parent();
-        let active_path = file.clone();
+    pub fn new(dir: PathBuf) -> Result<Self, DatabaseError> {
+        Self::with_options(
+            dir,
+            DEFAULT_MAX_ACTIVE_FILE_SIZE,
+            Compression::None,
+            EncryptionType::None,
+            None,
+        )
+    }
 
-        let mut write_position = 0;
+    pub fn with_max_active_file_size(
+        dir: PathBuf,
+        max_active_file_size: u64,
+    ) -> Result<Self, DatabaseError> {
+        Self::with_options(
+            dir,
+            max_active_file_size,
+            Compression::None,
+            EncryptionType::None,
+            None,
+        )
+    }
+
+    /// Compresses every value written by `set` with `compression`. Existing
+    /// records keep whatever codec they were written with since it's
+    /// recorded per-record, not per-store.
+    pub fn with_compression(dir: PathBuf, compression: Compression) -> Result<Self, DatabaseError> {
+        Self::with_options(
+            dir,
+            DEFAULT_MAX_ACTIVE_FILE_SIZE,
+            compression,
+            EncryptionType::None,
+            None,
+        )
+    }
+
+    /// Encrypts every value written by `set` with `encryption`, deriving the
+    /// data key from `passphrase` via Argon2 and a per-database random salt
+    /// persisted alongside the datafiles (generated on first use, reused on
+    /// every later open so the same passphrase always derives the same
+    /// key). As with `with_compression`, the cipher is recorded per-record,
+    /// not per-store, so existing records stay readable after a cipher
+    /// change. The store holds only the one key derived from `passphrase`:
+    /// records written under a different passphrase fail with
+    /// `DatabaseError::Decryption`.
+    pub fn with_encryption(
+        dir: PathBuf,
+        passphrase: &str,
+        encryption: EncryptionType,
+    ) -> Result<Self, DatabaseError> {
+        let data_key = Self::derive_data_key(&dir, passphrase)?;
+        Self::with_options(
+            dir,
+            DEFAULT_MAX_ACTIVE_FILE_SIZE,
+            Compression::None,
+            encryption,
+            Some(data_key),
+        )
+    }
+
+    fn salt_path(dir: &Path) -> PathBuf {
+        dir.join(SALT_FILE_NAME)
+    }
+
+    /// Derives a 32-byte data key from `passphrase` with Argon2, using a
+    /// random salt generated once per database and persisted to disk so
+    /// reopening the same database with the same passphrase always derives
+    /// the same key.
+    fn derive_data_key(dir: &Path, passphrase: &str) -> Result<[u8; 32], DatabaseError> {
+        fs::create_dir_all(dir)?;
+        let salt_path = Self::salt_path(dir);
+
+        let salt = if salt_path.exists() {
+            fs::read(&salt_path)?
+        } else {
+            let mut salt = vec![0u8; SALT_SIZE];
+            getrandom::fill(&mut salt).map_err(|e| DatabaseError::Io(io::Error::other(e.to_string())))?;
+            fs::write(&salt_path, &salt)?;
+            salt
+        };
+
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|_| DatabaseError::Decryption)?;
+        Ok(key)
+    }
+
+    fn with_options(
+        dir: PathBuf,
+        max_active_file_size: u64,
+        compression: Compression,
+        encryption: EncryptionType,
+        data_key: Option<[u8; 32]>,
+    ) -> Result<Self, DatabaseError> {
+        fs::create_dir_all(&dir)?;
+
+        let datafiles = Self::list_datafiles(&dir)?;
         let mut key_dir = DashMap::new();
 
-        if file.exists() {
-            DiskStore::init_key_dir(&active_path, &mut key_dir, &mut write_position)?;
+        let mut write_position = 0;
+        for path in &datafiles {
+            // Only the newest file's final write_position matters: it's
+            // the one we keep appending to below.
+            write_position = Self::init_key_dir(path, &mut key_dir)?;
         }
 
+        let is_new_store = datafiles.is_empty();
+        let active_path = match datafiles.last() {
+            Some(path) => path.clone(),
+            None => Self::new_active_path(&dir),
+        };
+
         // TODO Unwrap for now
-        let file = File::options()
+        let mut file = File::options()
             .create(true)
             .append(true)
             .read(true)
-            .open(file)?;
+            .open(&active_path)?;
+
+        if is_new_store {
+            Self::write_preamble(&mut file)?;
+            write_position = PREAMBLE_SIZE as u64;
+        }
 
         Ok(Self {
+            dir,
             file,
             active_path,
             key_dir,
             write_position,
+            max_active_file_size,
+            compression,
+            encryption,
+            data_key,
         })
     }
 
-    fn init_key_dir(
-        file_path: &Path,
-        key_dir: &mut KeyDirectory,
-        write_position: &mut u64,
-    ) -> Result<(), DatabaseError> {
-        let mut file = File::open(file_path)?;
+    fn new_active_path(dir: &Path) -> PathBuf {
+        // Millisecond resolution still collides when several rotations
+        // happen within the same millisecond (easy to hit with a small
+        // `max_active_file_size`, as the tests below do), so probe forward
+        // from the timestamp until the candidate name is actually free
+        // instead of trusting it alone.
+        let mut timestamp = chrono::Local::now().timestamp_millis();
+        loop {
+            let candidate = dir.join(format!("{timestamp}.{DATA_EXTENSION}"));
+            if !candidate.exists() {
+                break candidate;
+            }
+            timestamp += 1;
+        }
+    }
+
+    fn hintfile_path(data_path: &Path) -> PathBuf {
+        data_path.with_extension(HINT_EXTENSION)
+    }
+
+    /// Writes the magic + version preamble a brand-new datafile must start
+    /// with, so a later open can tell this is a caskdb datafile (and which
+    /// format version) before trusting anything else in it.
+    fn write_preamble(file: &mut File) -> Result<(), DatabaseError> {
+        file.write_all(&format::encode_preamble())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn list_datafiles(dir: &Path) -> Result<Vec<PathBuf>, DatabaseError> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(DATA_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        // Filenames are `<timestamp>.data`, so lexical order is timestamp
+        // order, oldest first.
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Replays `path` into `key_dir`, reading from its hint file instead of
+    /// the full datafile when one is available. Returns the write position
+    /// one past the last record, i.e. the file's length.
+    fn init_key_dir(path: &Path, key_dir: &mut KeyDirectory) -> Result<u64, DatabaseError> {
+        let hint_path = Self::hintfile_path(path);
+        if hint_path.exists() {
+            return Self::init_key_dir_from_hints(path, &hint_path, key_dir);
+        }
+
+        let mut file = File::open(path)?;
+
+        let mut preamble = [0u8; PREAMBLE_SIZE];
+        file.read_exact(&mut preamble)?;
+        format::validate_preamble(&preamble)?;
+
+        let mut write_position = PREAMBLE_SIZE as u64;
         let mut buf = [0u8; HEADER_SIZE];
 
         loop {
@@ -64,47 +418,339 @@ impl DiskStore {
                 return Err(DatabaseError::Format(FormatError::Header(n)));
             }
             let header = Header::decode(buf);
-            // TODO when value size is zero dont read
 
-            let mut key = vec![0; header.key_size() as usize];
+            // Read the key and value together so the crc stored in the
+            // header can be checked against the exact bytes written for
+            // this record, catching corruption during the startup scan
+            // instead of silently trusting it.
+            let mut rest = vec![0; header.total_size() as usize];
+            file.read_exact(&mut rest)?;
 
-            file.read_exact(&mut key)?;
+            let mut record_bytes = Vec::with_capacity(HEADER_SIZE + rest.len());
+            record_bytes.extend(buf);
+            record_bytes.extend(rest);
+            let record = Record::decode(record_bytes)?;
 
-            let key_entry = KeyEntry {
-                file_id: file_path.to_path_buf(),
-                total_size: header.total_size(),
-                value_offset: *write_position,
-                timestamp: header.timestamp(),
-            };
-            key_dir.insert(key, key_entry);
-            *write_position += HEADER_SIZE as u64 + header.total_size() as u64;
-            // Advance the cursor as we need to get next header entry
-            file.seek(std::io::SeekFrom::Current(header.value_size() as i64))?;
+            // A tombstone means the key was deleted after this point in
+            // the file, so the replay must forget it rather than insert it.
+            if record.is_tombstone() {
+                key_dir.remove(record.key());
+            } else {
+                let key_entry = KeyEntry {
+                    file_id: path.to_path_buf(),
+                    total_size: header.total_size(),
+                    value_offset: write_position,
+                    timestamp: header.timestamp(),
+                };
+                key_dir.insert(record.key().to_vec(), key_entry);
+            }
+            write_position += HEADER_SIZE as u64 + header.total_size() as u64;
+        }
+        Ok(write_position)
+    }
+
+    fn init_key_dir_from_hints(
+        data_path: &Path,
+        hint_path: &Path,
+        key_dir: &mut KeyDirectory,
+    ) -> Result<u64, DatabaseError> {
+        let mut file = File::open(hint_path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut cursor = 0usize;
+        while cursor < buf.len() {
+            if buf.len() - cursor < HINT_HEADER_SIZE {
+                return Err(DatabaseError::Format(FormatError::Hint(
+                    buf.len() - cursor,
+                    HINT_HEADER_SIZE,
+                )));
+            }
+            let timestamp = u32::from_be_bytes(buf[cursor..cursor + 4].try_into().unwrap());
+            let key_size = u32::from_be_bytes(buf[cursor + 4..cursor + 8].try_into().unwrap());
+            let value_size = u32::from_be_bytes(buf[cursor + 8..cursor + 12].try_into().unwrap());
+            let value_offset =
+                u64::from_be_bytes(buf[cursor + 12..cursor + 20].try_into().unwrap());
+            cursor += HINT_HEADER_SIZE;
+
+            if buf.len() - cursor < key_size as usize {
+                return Err(DatabaseError::Format(FormatError::Key(
+                    buf.len() - cursor,
+                    key_size as usize,
+                )));
+            }
+            let key = buf[cursor..cursor + key_size as usize].to_vec();
+            cursor += key_size as usize;
+
+            key_dir.insert(
+                key,
+                KeyEntry {
+                    file_id: data_path.to_path_buf(),
+                    total_size: key_size + value_size,
+                    value_offset,
+                    timestamp,
+                },
+            );
+        }
+
+        Ok(fs::metadata(data_path)?.len())
+    }
+
+    fn read_record_at(path: &Path, offset: u64, total_size: u32) -> Result<Record, DatabaseError> {
+        let mut file = File::open(path)?;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        let mut buf = vec![0; HEADER_SIZE + total_size as usize];
+        file.read_exact(&mut buf)?;
+        Ok(Record::decode(buf)?)
+    }
+
+    /// Flushes and seals the active file, then opens a fresh one so the
+    /// active file never grows past `max_active_file_size`.
+    fn rotate(&mut self) -> Result<(), DatabaseError> {
+        self.file.sync_all()?;
+
+        // Once sealed, nothing should be appending to this file anymore:
+        // key_dir entries pointing at it and a later merge() both assume
+        // it's closed for writing. Mark it read-only so an accidental
+        // further write fails loudly instead of silently drifting the file
+        // out of sync with what key_dir thinks is in it.
+        let mut permissions = fs::metadata(&self.active_path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&self.active_path, permissions)?;
+
+        let new_path = Self::new_active_path(&self.dir);
+        // `new_active_path` only hands back names that don't exist yet, but
+        // truncate anyway rather than trusting that and blindly appending:
+        // if this path were ever reused, append would write a second
+        // preamble mid-file instead of starting it clean.
+        let mut new_file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .read(true)
+            .open(&new_path)?;
+        Self::write_preamble(&mut new_file)?;
+
+        self.file = new_file;
+        self.active_path = new_path;
+        self.write_position = PREAMBLE_SIZE as u64;
+        Ok(())
+    }
+
+    fn encode_hint_entry(timestamp: u32, key_size: u32, value_size: u32, value_offset: u64, key: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HINT_HEADER_SIZE + key.len());
+        buf.extend(timestamp.to_be_bytes());
+        buf.extend(key_size.to_be_bytes());
+        buf.extend(value_size.to_be_bytes());
+        buf.extend(value_offset.to_be_bytes());
+        buf.extend(key);
+        buf
+    }
+
+    /// Rewrites every key still live in a non-active file into a fresh
+    /// merged datafile (plus a hint file alongside it), then drops the
+    /// superseded files. The active file is never touched. The merged data
+    /// and hint files are built at `.tmp` paths and only renamed into their
+    /// final names after both are fully written and fsynced, and the stale
+    /// files are only removed after that rename succeeds — so a crash at any
+    /// point leaves either the untouched previous files (tmp paths ignored
+    /// on the next open) or the fully-formed merged files, never a
+    /// truncated file sitting at a name `DiskStore::new` expects to be able
+    /// to read.
+    pub fn merge(&mut self) -> Result<(), DatabaseError> {
+        let stale_files: Vec<PathBuf> = Self::list_datafiles(&self.dir)?
+            .into_iter()
+            .filter(|path| *path != self.active_path)
+            .collect();
+
+        if stale_files.is_empty() {
+            return Ok(());
+        }
+
+        // The merged file must sort *before* the active file regardless of
+        // when merge runs, or a restart would mistake it for the active
+        // file (it's the newest filename) and silently stop appending to
+        // the real one. Counting down from the active file's own timestamp
+        // guarantees that ordering.
+        let active_timestamp: i64 = self
+            .active_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .unwrap_or(0);
+        let merged_path = {
+            let mut timestamp = active_timestamp - 1;
+            loop {
+                let candidate = self.dir.join(format!("{timestamp}.{DATA_EXTENSION}"));
+                if !stale_files.contains(&candidate) && !candidate.exists() {
+                    break candidate;
+                }
+                timestamp -= 1;
+            }
+        };
+
+        // Write to `.tmp` paths and only rename into the final names once the
+        // whole loop and both `sync_all()` calls below have succeeded. If we
+        // wrote directly at `merged_path`/its hint path, a crash partway
+        // through the loop would leave a truncated file sitting at the name
+        // a later `DiskStore::new` expects to read a complete, CRC-checked
+        // datafile from — turning a crash mid-merge into a store that can no
+        // longer be opened at all, rather than one that just ignores the
+        // unfinished merge.
+        let merged_tmp_path = merged_path.with_extension(format!("{DATA_EXTENSION}.tmp"));
+        let hint_path = Self::hintfile_path(&merged_path);
+        let hint_tmp_path = hint_path.with_extension(format!("{HINT_EXTENSION}.tmp"));
+
+        let mut merged_file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .read(true)
+            .open(&merged_tmp_path)?;
+        Self::write_preamble(&mut merged_file)?;
+        let mut hint_file = File::create(&hint_tmp_path)?;
+
+        let mut write_position = PREAMBLE_SIZE as u64;
+
+        for mut entry in self.key_dir.iter_mut() {
+            if !stale_files.contains(&entry.file_id) {
+                continue;
+            }
+
+            let stale = Self::read_record_at(&entry.file_id, entry.value_offset, entry.total_size)?;
+            // Relocate the bytes as-is: `stale.value` is still whatever
+            // codec and cipher wrote it, and both tags are carried forward
+            // too, so merge never needs to decrypt, decompress, or redo
+            // either.
+            let compression = stale.compression()?;
+            let encryption = stale.encryption()?;
+            let merged = Record::new(
+                stale.header.timestamp(),
+                stale.key().to_vec(),
+                stale.value,
+                compression,
+                encryption,
+            );
+
+            let key = merged.key().to_vec();
+            let timestamp = merged.header.timestamp();
+            let key_size = merged.header.key_size();
+            let value_size = merged.header.value_size();
+
+            hint_file.write_all(&Self::encode_hint_entry(
+                timestamp,
+                key_size,
+                value_size,
+                write_position,
+                &key,
+            ))?;
+            merged_file.write_all(&merged.encode())?;
+
+            entry.file_id = merged_path.clone();
+            entry.value_offset = write_position;
+            entry.total_size = key_size + value_size;
+            entry.timestamp = timestamp;
+
+            write_position += HEADER_SIZE as u64 + (key_size + value_size) as u64;
         }
+
+        merged_file.sync_all()?;
+        hint_file.sync_all()?;
+
+        fs::rename(&merged_tmp_path, &merged_path)?;
+        fs::rename(&hint_tmp_path, &hint_path)?;
+
+        for path in stale_files {
+            fs::remove_file(&path)?;
+            let hint_path = Self::hintfile_path(&path);
+            if hint_path.exists() {
+                fs::remove_file(hint_path)?;
+            }
+        }
+
         Ok(())
     }
+
+    /// Batched lookup: resolves every key against `key_dir` first (a
+    /// `DashMap`, so this never blocks a concurrent writer), then reads the
+    /// hits back in ascending `(file, offset)` order so each file's reads
+    /// move forward instead of bouncing the disk head around. Keys that
+    /// aren't present are silently omitted rather than erroring, since a
+    /// partial hit is the expected outcome of a batch lookup.
+    pub fn get_many<K: Encode>(
+        &self,
+        keys: &[K],
+    ) -> Result<HashMap<Vec<u8>, Vec<u8>>, DatabaseError> {
+        let mut hits: Vec<(Vec<u8>, KeyEntry)> = Vec::new();
+        for key in keys {
+            let key_bytes = bincode::encode_to_vec(key, CONFIG)?;
+            if let Some(entry) = self.key_dir.get(&key_bytes) {
+                hits.push((key_bytes, entry.clone()));
+            }
+        }
+        hits.sort_by(|(_, a), (_, b)| {
+            a.file_id
+                .cmp(&b.file_id)
+                .then(a.value_offset.cmp(&b.value_offset))
+        });
+
+        let mut results = HashMap::with_capacity(hits.len());
+        for (key_bytes, entry) in hits {
+            let record = Self::read_record_at(&entry.file_id, entry.value_offset, entry.total_size)?;
+
+            let encryption = record.encryption()?;
+            let compression = record.compression()?;
+            let value = if encryption == EncryptionType::None {
+                record.value
+            } else {
+                let data_key = self.data_key.ok_or(DatabaseError::Decryption)?;
+                encryption.decrypt(&data_key, &record.value)?
+            };
+            let value = compression.decompress(&value)?;
+
+            results.insert(key_bytes, value);
+        }
+
+        Ok(results)
+    }
 }
 
 impl KeyValueStore for DiskStore {
-    fn get<K: Encode>(&mut self, key: K) -> Result<Option<Vec<u8>>, DatabaseError> {
+    fn get<K: Encode>(&self, key: K) -> Result<Option<Vec<u8>>, DatabaseError> {
         let key = bincode::encode_to_vec(key, CONFIG)?;
         let Some(hint) = self.key_dir.get(&key) else {
             return Ok(None);
         };
 
-        self.file
-            .seek(std::io::SeekFrom::Start(hint.value_offset))?;
-
-        let mut buf = vec![0; HEADER_SIZE + hint.total_size as usize];
-        self.file.read_exact(&mut buf)?;
-        let record = Record::decode(buf)?;
+        // Always reopen the file by path rather than reusing `self.file`,
+        // even for the active file: `key_dir` is already safe to read
+        // concurrently with a writer appending, and doing the same here
+        // keeps `get` on `&self` instead of needing exclusive access.
+        let record = Self::read_record_at(&hint.file_id, hint.value_offset, hint.total_size)?;
 
-        Ok(Some(record.value))
+        let encryption = record.encryption()?;
+        let compression = record.compression()?;
+        let value = if encryption == EncryptionType::None {
+            record.value
+        } else {
+            let data_key = self.data_key.ok_or(DatabaseError::Decryption)?;
+            encryption.decrypt(&data_key, &record.value)?
+        };
+        let value = compression.decompress(&value)?;
+        Ok(Some(value))
     }
 
     fn set<K: Encode, V: Encode>(&mut self, key: K, value: V) -> Result<(), DatabaseError> {
         let now = chrono::Local::now().timestamp() as u32;
-        let record = Record::try_new(now, key, value)?;
+
+        let key = bincode::encode_to_vec(key, CONFIG)?;
+        let value = bincode::encode_to_vec(value, CONFIG)?;
+        let value = self.compression.compress(&value)?;
+        let value = match self.data_key {
+            Some(data_key) => self.encryption.encrypt(&data_key, &value)?,
+            None => value,
+        };
+        let record = Record::new(now, key, value, self.compression, self.encryption);
 
         // Update key_dir
         let key_entry = KeyEntry {
@@ -127,27 +773,48 @@ impl KeyValueStore for DiskStore {
         // Fsync for more durability
         self.file.sync_all()?;
 
+        if self.write_position >= self.max_active_file_size {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    fn delete<K: Encode>(&mut self, key: K) -> Result<(), DatabaseError> {
+        let key = bincode::encode_to_vec(key, CONFIG)?;
+        let record = Record::new_tombstone(chrono::Local::now().timestamp() as u32, key.clone());
+
+        self.write_position += HEADER_SIZE as u64 + record.header.total_size() as u64;
+
+        let data = record.encode();
+        let _ = self.file.write(&data)?;
+        self.file.sync_all()?;
+
+        // The tombstone on disk is only there so a restart's replay forgets
+        // the key too; the live key_dir can forget it right away.
+        self.key_dir.remove(&key);
+
+        if self.write_position >= self.max_active_file_size {
+            self.rotate()?;
+        }
+
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::remove_file;
+    use std::fs::remove_dir_all;
 
     use super::*;
-    use tempfile::NamedTempFile;
+    use tempfile::tempdir;
 
-    fn setup() -> NamedTempFile {
-        NamedTempFile::new().unwrap()
-    }
-
-    struct TestFile(PathBuf);
+    struct TestDir(PathBuf);
 
-    impl Drop for TestFile {
+    impl Drop for TestDir {
         fn drop(&mut self) {
-            if self.0.exists() && self.0.is_file() {
-                let _ = remove_file(self.0.clone());
+            if self.0.exists() {
+                let _ = remove_dir_all(&self.0);
             }
         }
     }
@@ -166,9 +833,9 @@ mod tests {
 
     #[test]
     fn test_get() {
-        let file = setup();
+        let dir = tempdir().unwrap();
 
-        let mut store = DiskStore::new(file.path().to_path_buf()).unwrap();
+        let mut store = DiskStore::new(dir.path().to_path_buf()).unwrap();
         store.set("name", "jojo").unwrap();
 
         assert_eq!(
@@ -179,16 +846,16 @@ mod tests {
 
     #[test]
     fn test_invalid_key() {
-        let file = setup();
-        let mut store = DiskStore::new(file.path().to_path_buf()).unwrap();
+        let dir = tempdir().unwrap();
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
         assert_eq!(store.get("some key").unwrap(), None)
     }
 
     #[test]
     fn test_persistence() {
-        let file = setup();
+        let dir = tempdir().unwrap();
 
-        let mut store = DiskStore::new(file.path().to_path_buf()).unwrap();
+        let mut store = DiskStore::new(dir.path().to_path_buf()).unwrap();
         let tests = kv_pairs();
         for (key, value) in tests.iter() {
             store.set(*key, *value).unwrap();
@@ -198,7 +865,7 @@ mod tests {
             );
         }
 
-        let mut store = DiskStore::new(file.path().to_path_buf()).unwrap();
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
         for (key, value) in tests {
             assert_eq!(
                 store.get(key).unwrap().unwrap(),
@@ -209,9 +876,9 @@ mod tests {
 
     #[test]
     fn test_deletion() {
-        let file = setup();
+        let dir = tempdir().unwrap();
 
-        let mut store = DiskStore::new(file.path().to_path_buf()).unwrap();
+        let mut store = DiskStore::new(dir.path().to_path_buf()).unwrap();
         let tests = kv_pairs();
         for (key, value) in tests.iter() {
             store.set(*key, *value).unwrap();
@@ -221,20 +888,14 @@ mod tests {
             );
         }
         for (key, _) in tests.iter() {
-            store.set(*key, "").unwrap();
-            assert_eq!(
-                store.get(*key).unwrap().unwrap(),
-                bincode::encode_to_vec("", CONFIG).unwrap()
-            );
+            store.delete(*key).unwrap();
+            assert_eq!(store.get(*key).unwrap(), None);
         }
         store.set("end", "yes").unwrap();
 
-        let mut store = DiskStore::new(file.path().to_path_buf()).unwrap();
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
         for (key, _) in tests {
-            assert_eq!(
-                store.get(key).unwrap().unwrap(),
-                bincode::encode_to_vec("", CONFIG).unwrap()
-            );
+            assert_eq!(store.get(key).unwrap(), None);
         }
         assert_eq!(
             store.get("end").unwrap().unwrap(),
@@ -244,8 +905,8 @@ mod tests {
 
     #[test]
     fn test_get_new_file() {
-        let file = TestFile(PathBuf::from("test.db"));
-        let mut store = DiskStore::new(file.0.to_path_buf()).unwrap();
+        let dir = TestDir(PathBuf::from("test_caskdb_new_dir"));
+        let mut store = DiskStore::new(dir.0.clone()).unwrap();
         store.set("name", "jojo").unwrap();
 
         assert_eq!(
@@ -253,11 +914,290 @@ mod tests {
             bincode::encode_to_vec("jojo", CONFIG).unwrap()
         );
 
-        let mut store = DiskStore::new(file.0.to_path_buf()).unwrap();
+        let store = DiskStore::new(dir.0.clone()).unwrap();
 
         assert_eq!(
             store.get("name").unwrap().unwrap(),
             bincode::encode_to_vec("jojo", CONFIG).unwrap()
         );
     }
+
+    #[test]
+    fn test_rotation_creates_new_active_file() {
+        let dir = tempdir().unwrap();
+        // Small enough that a handful of records force a rotation.
+        let mut store = DiskStore::with_max_active_file_size(dir.path().to_path_buf(), 64).unwrap();
+
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+
+        let datafiles = DiskStore::list_datafiles(dir.path()).unwrap();
+        assert!(datafiles.len() > 1);
+        assert_eq!(datafiles.last().unwrap(), &store.active_path);
+
+        // Every sealed file should now be read-only; the active file is the
+        // only one still open for writing.
+        for path in &datafiles[..datafiles.len() - 1] {
+            assert!(fs::metadata(path).unwrap().permissions().readonly());
+        }
+
+        for (key, value) in kv_pairs() {
+            assert_eq!(
+                store.get(key).unwrap().unwrap(),
+                bincode::encode_to_vec(value, CONFIG).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_reclaims_stale_files_and_writes_hints() {
+        let dir = tempdir().unwrap();
+        let mut store = DiskStore::with_max_active_file_size(dir.path().to_path_buf(), 64).unwrap();
+
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+        // Overwrite every key so the earlier files are entirely stale.
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+
+        let before = DiskStore::list_datafiles(dir.path()).unwrap();
+        assert!(before.len() > 1);
+
+        store.merge().unwrap();
+
+        let after = DiskStore::list_datafiles(dir.path()).unwrap();
+        // Only the still-active file plus the freshly merged one remain.
+        assert_eq!(after.len(), 2);
+        assert!(after.contains(&store.active_path));
+
+        let merged_path = after.iter().find(|p| **p != store.active_path).unwrap();
+        assert!(DiskStore::hintfile_path(merged_path).exists());
+
+        for (key, value) in kv_pairs() {
+            assert_eq!(
+                store.get(key).unwrap().unwrap(),
+                bincode::encode_to_vec(value, CONFIG).unwrap()
+            );
+        }
+
+        // Reopening must be able to rebuild key_dir from the hint file.
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
+        for (key, value) in kv_pairs() {
+            assert_eq!(
+                store.get(key).unwrap().unwrap(),
+                bincode::encode_to_vec(value, CONFIG).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_crash_mid_merge_leaves_store_openable() {
+        let dir = tempdir().unwrap();
+        let mut store = DiskStore::with_max_active_file_size(dir.path().to_path_buf(), 64).unwrap();
+
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+
+        store.merge().unwrap();
+
+        // Simulate a crash partway through a *second* merge: nothing stale
+        // is left to merge here, but stand in a truncated `.tmp` pair for
+        // what a killed merge would have left behind, and make sure a
+        // stray `.tmp` file never stops the store from opening.
+        let after = DiskStore::list_datafiles(dir.path()).unwrap();
+        let merged_path = after.iter().find(|p| **p != store.active_path).unwrap();
+        fs::write(merged_path.with_extension("data.tmp"), b"not a real datafile").unwrap();
+        fs::write(
+            DiskStore::hintfile_path(merged_path).with_extension("hint.tmp"),
+            b"not a real hint file",
+        )
+        .unwrap();
+
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
+        for (key, value) in kv_pairs() {
+            assert_eq!(
+                store.get(key).unwrap().unwrap(),
+                bincode::encode_to_vec(value, CONFIG).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "compress-zstd")]
+    fn test_compressed_values_round_trip() {
+        let dir = tempdir().unwrap();
+        let mut store =
+            DiskStore::with_compression(dir.path().to_path_buf(), Compression::Zstd).unwrap();
+
+        let large_value = "repeat me ".repeat(100);
+        store.set("key", large_value.as_str()).unwrap();
+
+        assert_eq!(
+            store.get("key").unwrap().unwrap(),
+            bincode::encode_to_vec(large_value.as_str(), CONFIG).unwrap()
+        );
+
+        // A restart must be able to decompress records written by a
+        // previous session too.
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(
+            store.get("key").unwrap().unwrap(),
+            bincode::encode_to_vec(large_value.as_str(), CONFIG).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compression_unavailable_surfaces_format_error() {
+        let record = Record::new(
+            chrono::Local::now().timestamp() as u32,
+            b"k".to_vec(),
+            b"v".to_vec(),
+            Compression::Lzma,
+            EncryptionType::None,
+        );
+
+        #[cfg(not(feature = "compress-lzma"))]
+        assert!(matches!(
+            record.compression().unwrap().decompress(b"v"),
+            Err(DatabaseError::Format(FormatError::CompressionUnavailable(
+                Compression::Lzma
+            )))
+        ));
+        #[cfg(feature = "compress-lzma")]
+        let _ = record;
+    }
+
+    #[test]
+    #[cfg(feature = "encrypt-aes-gcm")]
+    fn test_encrypted_values_round_trip() {
+        let dir = tempdir().unwrap();
+        let mut store =
+            DiskStore::with_encryption(dir.path().to_path_buf(), "hunter2", EncryptionType::AesGcm)
+                .unwrap();
+
+        store.set("key", "top secret").unwrap();
+        assert_eq!(
+            store.get("key").unwrap().unwrap(),
+            bincode::encode_to_vec("top secret", CONFIG).unwrap()
+        );
+
+        // A restart with the same passphrase must re-derive the same data
+        // key (from the persisted salt) and decrypt records written by a
+        // previous session.
+        let store =
+            DiskStore::with_encryption(dir.path().to_path_buf(), "hunter2", EncryptionType::AesGcm)
+                .unwrap();
+        assert_eq!(
+            store.get("key").unwrap().unwrap(),
+            bincode::encode_to_vec("top secret", CONFIG).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encrypt-aes-gcm")]
+    fn test_wrong_passphrase_fails_authentication() {
+        let dir = tempdir().unwrap();
+        let mut store =
+            DiskStore::with_encryption(dir.path().to_path_buf(), "hunter2", EncryptionType::AesGcm)
+                .unwrap();
+        store.set("key", "top secret").unwrap();
+
+        let store = DiskStore::with_encryption(
+            dir.path().to_path_buf(),
+            "wrong passphrase",
+            EncryptionType::AesGcm,
+        )
+        .unwrap();
+        assert!(matches!(store.get("key"), Err(DatabaseError::Decryption)));
+    }
+
+    #[test]
+    fn test_encryption_unavailable_surfaces_format_error() {
+        let record = Record::new(
+            chrono::Local::now().timestamp() as u32,
+            b"k".to_vec(),
+            b"v".to_vec(),
+            Compression::None,
+            EncryptionType::ChaCha20Poly1305,
+        );
+
+        #[cfg(not(feature = "encrypt-chacha20poly1305"))]
+        assert!(matches!(
+            record
+                .encryption()
+                .unwrap()
+                .decrypt(&[0u8; 32], b"v"),
+            Err(DatabaseError::Format(FormatError::EncryptionUnavailable(
+                EncryptionType::ChaCha20Poly1305
+            )))
+        ));
+        #[cfg(feature = "encrypt-chacha20poly1305")]
+        let _ = record;
+    }
+
+    #[test]
+    fn test_merge_drops_deleted_keys() {
+        let dir = tempdir().unwrap();
+        let mut store = DiskStore::with_max_active_file_size(dir.path().to_path_buf(), 64).unwrap();
+
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+        store.delete("dune").unwrap();
+
+        store.merge().unwrap();
+        assert_eq!(store.get("dune").unwrap(), None);
+
+        let store = DiskStore::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(store.get("dune").unwrap(), None);
+        assert_eq!(
+            store.get("hamlet").unwrap().unwrap(),
+            bincode::encode_to_vec("shakespeare", CONFIG).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_many_returns_only_present_keys() {
+        let dir = tempdir().unwrap();
+        let mut store = DiskStore::new(dir.path().to_path_buf()).unwrap();
+
+        for (key, value) in kv_pairs() {
+            store.set(key, value).unwrap();
+        }
+
+        let requested = vec!["hamlet", "othello", "no such key"];
+        let found = store.get_many(&requested).unwrap();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            found[&bincode::encode_to_vec("hamlet", CONFIG).unwrap()],
+            bincode::encode_to_vec("shakespeare", CONFIG).unwrap()
+        );
+        assert_eq!(
+            found[&bincode::encode_to_vec("othello", CONFIG).unwrap()],
+            bincode::encode_to_vec("shakespeare", CONFIG).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_does_not_need_exclusive_access() {
+        let dir = tempdir().unwrap();
+        let mut store = DiskStore::new(dir.path().to_path_buf()).unwrap();
+        store.set("name", "jojo").unwrap();
+
+        // `KeyValueStore::get` only needs `&self`, so a shared reference is
+        // enough to read back a value that was written through `&mut self`.
+        let shared: &DiskStore = &store;
+        assert_eq!(
+            shared.get("name").unwrap().unwrap(),
+            bincode::encode_to_vec("jojo", CONFIG).unwrap()
+        );
+    }
 }
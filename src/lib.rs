@@ -1,4 +1,7 @@
+#[cfg(feature = "async-tokio")]
+mod async_store;
 mod disk_store;
+#[path = "database/format.rs"]
 mod format;
 
 use bincode::{Encode, error::EncodeError};
@@ -6,6 +9,9 @@ use dashmap::DashMap;
 use std::path::PathBuf;
 use thiserror::Error;
 
+#[cfg(feature = "async-tokio")]
+pub use async_store::{AsyncDiskStore, AsyncKeyValueStore};
+pub use disk_store::DiskStore;
 use format::FormatError;
 
 #[derive(Error, Debug)]
@@ -16,11 +22,13 @@ pub enum DatabaseError {
     Format(#[from] FormatError),
     #[error(transparent)]
     Encode(#[from] EncodeError),
+    #[error("failed to authenticate record: wrong passphrase or corrupted/tampered data")]
+    Decryption,
 }
 
 type KeyDirectory = DashMap<Vec<u8>, KeyEntry>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct KeyEntry {
     file_id: PathBuf,
     total_size: u32,
@@ -29,6 +37,9 @@ struct KeyEntry {
 }
 
 pub trait KeyValueStore {
-    fn get<K: Encode>(&mut self, key: K) -> Result<Option<Vec<u8>>, DatabaseError>;
+    /// Only needs `&self`: `key_dir` is a `DashMap`, so lookups never block
+    /// a concurrent `set`/`delete` on the same store.
+    fn get<K: Encode>(&self, key: K) -> Result<Option<Vec<u8>>, DatabaseError>;
     fn set<K: Encode, V: Encode>(&mut self, key: K, value: V) -> Result<(), DatabaseError>;
+    fn delete<K: Encode>(&mut self, key: K) -> Result<(), DatabaseError>;
 }